@@ -0,0 +1,197 @@
+//! Legalization of instructions that have no direct hardware encoding by calling out to a
+//! runtime library function.
+//!
+//! This is the `Legalize::LibCall` strategy: the controlling ISA has no encoding for the
+//! instruction at all (as opposed to `Expand`/`Narrow`, which rewrite it in terms of other
+//! Cretonne instructions), so the only option is to replace it with a call to a well-known
+//! runtime symbol, the same way e.g. `__divdf3` stands in for `fdiv.f64` on soft-float targets.
+
+use flowgraph::ControlFlowGraph;
+use ir::{AbiParam, Cursor, DataFlowGraph, ExtFuncData, ExternalName, InstBuilder, Opcode,
+         Signature, Type};
+use ir::types;
+use isa::TargetIsa;
+
+/// A runtime library routine that can stand in for an instruction the target ISA can't encode.
+struct LibCall {
+    /// The opcode/controlling-type combination this entry legalizes, and the name of the ISA it
+    /// applies to (`None` matches any ISA).
+    key: (Option<&'static str>, Opcode, Type),
+    /// The symbol name that the runtime/libm must provide, e.g. `"__divdf3"`.
+    name: &'static str,
+    /// The signature of the call, expressed in terms of the controlling type variable `ctrl_ty`
+    /// and the ISA's calling convention.
+    make_sig: fn(&TargetIsa, Type) -> Signature,
+}
+
+/// The reserved `ExternalName::user` namespace for libcall symbols.
+///
+/// The legalizer only has a `&mut DataFlowGraph` to work with here, not the `cton_module::Module`
+/// that owns the real declaration table `ExternalName`s normally resolve through, so libcalls
+/// can't be declared the way an ordinary cross-function call is. Instead, a libcall's name is
+/// encoded as `ExternalName::user(LIBCALL_NAMESPACE, index)`, where `index` is its position in
+/// `TABLE` (see `lookup`/`name_for_index`); a `Module` embedding this legalizer is expected to
+/// pre-declare every entry of that table at this namespace before compiling any function that
+/// might legalize to one, and a backend's relocation resolution needs to special-case this
+/// namespace rather than treating it as an ordinary user-declared function. Wiring that
+/// pre-declaration into `cton_module::Module` is out of scope here (that crate isn't part of this
+/// tree); the real fix is a dedicated `ExternalName::LibCall` variant in `ir` that a backend
+/// resolves directly by well-known symbol name, bypassing the declaration table entirely.
+pub const LIBCALL_NAMESPACE: u32 = u32::max_value();
+
+/// All known opcode/type/ISA combinations that legalize to a runtime call, indexed by position.
+/// This only grows; new entries can be added as new combinations turn up without hardware
+/// support. Most entries apply to any ISA (`key.0` is `None`); a `Some(name)` entry overrides the
+/// `None` entry for that same `(opcode, ctrl_ty)` on the ISA named `name`, so two targets that
+/// both need a libcall for the same instruction can still disagree on its symbol or signature.
+const TABLE: &[LibCall] = &[
+    LibCall { key: (None, Opcode::Fdiv, types::F64), name: "__divdf3", make_sig: binary_sig },
+    LibCall { key: (None, Opcode::Fdiv, types::F32), name: "__divsf3", make_sig: binary_sig },
+    LibCall { key: (None, Opcode::Sdiv, types::I64), name: "__divdi3", make_sig: binary_sig },
+    LibCall { key: (None, Opcode::Udiv, types::I64), name: "__udivdi3", make_sig: binary_sig },
+    LibCall { key: (None, Opcode::Srem, types::I64), name: "__moddi3", make_sig: binary_sig },
+    LibCall { key: (None, Opcode::Urem, types::I64), name: "__umoddi3", make_sig: binary_sig },
+    // Convert a 64-bit signed integer to `f32`, e.g. on an ISA with no integer-to-float
+    // conversion instruction at all.
+    LibCall {
+        key: (None, Opcode::FcvtFromSint, types::I64),
+        name: "__floatdisf",
+        make_sig: fcvt_from_sint_sig,
+    },
+];
+
+/// Look up the runtime routine for `opcode` at the controlling type `ctrl_ty` on `isa`, if one is
+/// known. `TABLE` is shared by all ISAs, but an entry can be keyed to one ISA by name, so two ISAs
+/// that both need a libcall for the same `(opcode, ctrl_ty)` can still name and/or sign it
+/// differently.
+fn lookup(isa: &TargetIsa, opcode: Opcode, ctrl_ty: Type) -> Option<(usize, &'static LibCall)> {
+    select(TABLE, isa.name(), opcode, ctrl_ty)
+}
+
+/// The pure selection logic behind `lookup`, factored out over an explicit `table` so it can be
+/// exercised without a real `TargetIsa` to hand.
+fn select(
+    table: &'static [LibCall],
+    isa_name: &str,
+    opcode: Opcode,
+    ctrl_ty: Type,
+) -> Option<(usize, &'static LibCall)> {
+    // An ISA-specific entry, if any, takes priority over the ISA-agnostic one for the same
+    // opcode/type. Narrow to entries that apply to `isa_name` at all (generic, or specific to
+    // `isa_name`) *before* picking a winner: otherwise an entry specific to some other ISA can tie
+    // with (and, since `max_by_key` breaks ties toward the last element, beat) a generic fallback
+    // entry.
+    table
+        .iter()
+        .enumerate()
+        .filter(|&(_, entry)| entry.key.1 == opcode && entry.key.2 == ctrl_ty)
+        .filter(|&(_, entry)| entry.key.0.is_none() || entry.key.0 == Some(isa_name))
+        .max_by_key(|&(_, entry)| entry.key.0.is_some())
+}
+
+/// Recover the runtime symbol name for the libcall `index` identifies in `TABLE`, as encoded into
+/// an `ExternalName::user(LIBCALL_NAMESPACE, index)` by `expand_libcall`. Used by a backend to
+/// resolve a call site once `ExternalName::user`'s namespace marks it as a libcall rather than an
+/// ordinary module-declared function.
+pub fn name_for_index(index: u32) -> Option<&'static str> {
+    TABLE.get(index as usize).map(|entry| entry.name)
+}
+
+fn binary_sig(isa: &TargetIsa, ctrl_ty: Type) -> Signature {
+    let mut sig = Signature::new(isa.default_call_conv());
+    sig.params.push(AbiParam::new(ctrl_ty));
+    sig.params.push(AbiParam::new(ctrl_ty));
+    sig.returns.push(AbiParam::new(ctrl_ty));
+    sig
+}
+
+fn fcvt_from_sint_sig(isa: &TargetIsa, ctrl_ty: Type) -> Signature {
+    let mut sig = Signature::new(isa.default_call_conv());
+    sig.params.push(AbiParam::new(ctrl_ty));
+    sig.returns.push(AbiParam::new(types::F32));
+    sig
+}
+
+/// Try to legalize `inst` by replacing it with a call to a runtime library routine.
+///
+/// Returns `true` if the instruction was replaced.
+pub fn expand_libcall(
+    dfg: &mut DataFlowGraph,
+    _cfg: &mut ControlFlowGraph,
+    pos: &mut Cursor,
+    isa: &TargetIsa,
+) -> bool {
+    let inst = pos.current_inst().expect("got disconnected instruction");
+    let opcode = dfg[inst].opcode();
+    let ctrl_ty = dfg.ctrl_typevar(inst);
+
+    let (index, libcall) = match lookup(isa, opcode, ctrl_ty) {
+        Some(found) => found,
+        None => return false,
+    };
+
+    let sig = dfg.import_signature((libcall.make_sig)(isa, ctrl_ty));
+    let index = index as u32;
+    let name = dfg.import_function(ExtFuncData {
+        name: ExternalName::user(LIBCALL_NAMESPACE, index),
+        signature: sig,
+    });
+
+    // Every opcode this table knows about has exactly one result.
+    let old_result = dfg.first_result(inst);
+    let args = dfg.inst_args(inst).to_vec();
+    pos.remove_inst();
+    let call = pos.ins().call(name, &args);
+    let new_result = dfg.inst_results(call)[0];
+    dfg.change_to_alias(old_result, new_result);
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{name_for_index, select, LibCall, TABLE};
+    use ir::Opcode;
+    use ir::types;
+
+    #[test]
+    fn name_for_index_round_trips_through_the_table() {
+        for (index, entry) in TABLE.iter().enumerate() {
+            assert_eq!(name_for_index(index as u32), Some(entry.name));
+        }
+    }
+
+    #[test]
+    fn name_for_index_is_none_past_the_end_of_the_table() {
+        assert_eq!(name_for_index(TABLE.len() as u32), None);
+    }
+
+    // A generic entry alongside an entry specific to some *other* ISA than the one queried. Before
+    // the fix, `max_by_key` ranked both as equally non-matching, and ties resolve to the last
+    // element, so the other-ISA entry could win and then get dropped by the trailing filter,
+    // losing the generic fallback entirely.
+    static MIXED_TABLE: &[LibCall] = &[
+        LibCall {
+            key: (None, Opcode::Fdiv, types::F64),
+            name: "__divdf3",
+            make_sig: |_, _| unreachable!(),
+        },
+        LibCall {
+            key: (Some("other_isa"), Opcode::Fdiv, types::F64),
+            name: "__divdf3_other_isa",
+            make_sig: |_, _| unreachable!(),
+        },
+    ];
+
+    #[test]
+    fn generic_entry_is_found_when_only_a_different_isa_overrides_it() {
+        let found = select(MIXED_TABLE, "this_isa", Opcode::Fdiv, types::F64);
+        assert_eq!(found.map(|(_, entry)| entry.name), Some("__divdf3"));
+    }
+
+    #[test]
+    fn isa_specific_entry_wins_over_the_generic_one_on_its_own_isa() {
+        let found = select(MIXED_TABLE, "other_isa", Opcode::Fdiv, types::F64);
+        assert_eq!(found.map(|(_, entry)| entry.name), Some("__divdf3_other_isa"));
+    }
+}
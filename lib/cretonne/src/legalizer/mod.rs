@@ -22,6 +22,8 @@ use bitset::BitSet;
 use ir::instructions::ValueTypeSet;
 
 mod boundary;
+mod libcall;
+mod promote;
 mod split;
 
 /// Legalize `func` for `isa`.
@@ -29,10 +31,15 @@ mod split;
 /// - Transform any instructions that don't have a legal representation in `isa`.
 /// - Fill out `func.encodings`.
 ///
+/// `legalize_fuel` bounds how many times in a row the same spot in an EBB may be re-expanded
+/// before `legalize_function` concludes the responsible `Legalize` action is looping and aborts
+/// with an error; callers that expect unusually long legalization chains (e.g. a pathological
+/// test case) can raise it above `DEFAULT_LEGALIZE_FUEL`.
 pub fn legalize_function(func: &mut Function,
                          cfg: &mut ControlFlowGraph,
                          domtree: &DominatorTree,
-                         isa: &TargetIsa) {
+                         isa: &TargetIsa,
+                         legalize_fuel: u32) {
     boundary::legalize_signatures(func, isa);
 
     func.encodings.resize(func.dfg.num_insts());
@@ -48,6 +55,12 @@ pub fn legalize_function(func: &mut Function,
         // double back when replacing instructions.
         let mut prev_pos = pos.position();
 
+        // Count how many times in a row we've doubled back to `prev_pos` without reaching a
+        // settled instruction. An unsound `XForm` can keep expanding the same spot forever, and
+        // this turns that into a diagnosable error instead of a hang. The counter is cheap in
+        // the common case: it resets to 0 every time an instruction settles.
+        let mut fuel = 0u32;
+
         while let Some(inst) = pos.next_inst() {
             let opcode = func.dfg[inst].opcode();
 
@@ -79,35 +92,53 @@ pub fn legalize_function(func: &mut Function,
                     // 2. Legalize::Narrow: Split the controlling type variable into high and low
                     //    parts. This applies both to SIMD vector types which can be halved and to
                     //    integer types such as `i64` used on a 32-bit ISA. ().
-                    // 3. TODO: Promote the controlling type variable to a larger type. This
-                    //    typically means expressing `i8` and `i16` arithmetic in terms if `i32`
-                    //    operations on RISC targets. (It may or may not be beneficial to promote
-                    //    small vector types versus splitting them.)
-                    // 4. TODO: Convert to library calls. For example, floating point operations on
-                    //    an ISA with no IEEE 754 support.
+                    // 3. Legalize::Promote: Promote the controlling type variable to a larger
+                    //    type. This typically means expressing `i8` and `i16` arithmetic in
+                    //    terms of `i32` operations on RISC targets. (It may or may not be
+                    //    beneficial to promote small vector types versus splitting them.)
+                    // 4. Legalize::LibCall: Replace the instruction with a call to a runtime
+                    //    library routine. For example, floating point operations on an ISA with
+                    //    no IEEE 754 support.
                     let changed = match action {
                         Legalize::Expand => expand(&mut func.dfg, cfg, &mut pos),
                         Legalize::Narrow => narrow(&mut func.dfg, cfg, &mut pos),
+                        Legalize::Promote => promote::expand_promote(&mut func.dfg, cfg, &mut pos),
+                        Legalize::LibCall => {
+                            libcall::expand_libcall(&mut func.dfg, cfg, &mut pos, isa)
+                        }
                     };
                     // If the current instruction was replaced, we need to double back and revisit
                     // the expanded sequence. This is both to assign encodings and possible to
                     // expand further.
-                    // There's a risk of infinite looping here if the legalization patterns are
-                    // unsound. Should we attempt to detect that?
                     if changed {
+                        fuel += 1;
+                        if fuel > legalize_fuel {
+                            panic!(
+                                "legalization of `{}` for {} does not appear to terminate after \
+                                 {} expansion(s); the `XForm` is likely unsound",
+                                opcode,
+                                isa.name(),
+                                legalize_fuel
+                            );
+                        }
                         pos.set_position(prev_pos);
                         continue;
                     }
                 }
             }
 
-            // Remember this position in case we need to double back.
+            // This instruction settled: reset the fuel counter and remember this position in
+            // case we need to double back.
+            fuel = 0;
             prev_pos = pos.position();
         }
     }
     func.encodings.resize(func.dfg.num_insts());
 }
 
+/// Default value for `legalize_function`'s `legalize_fuel` parameter.
+pub const DEFAULT_LEGALIZE_FUEL: u32 = 1000;
+
 // Include legalization patterns that were generated by `gen_legalizer.py` from the `XForms` in
 // `meta/cretonne/legalize.py`.
 //
@@ -0,0 +1,185 @@
+//! Legalization of narrow integer arithmetic by promoting it to a wider type.
+//!
+//! This is the `Legalize::Promote` strategy: some RISC targets don't encode `i8`/`i16`
+//! arithmetic directly and instead expect it to be expressed as `i32` operations with the
+//! operands widened and the result truncated back down.
+
+use flowgraph::ControlFlowGraph;
+use ir::condcodes::IntCC;
+use ir::{self, Cursor, DataFlowGraph, InstBuilder, Opcode};
+use ir::types;
+
+/// Does `opcode` need sign extension (rather than zero extension) of its narrow operands when
+/// promoted to a wider type, and does it have a direct `i32` counterpart we know how to emit?
+///
+/// Returns `None` if `opcode` isn't one of the binary arithmetic/logic ops this legalization
+/// knows how to promote. `Icmp` is handled separately by `expand_promote`, since its signedness
+/// comes from the instruction's condition code rather than being fixed per opcode.
+fn signedness(opcode: Opcode) -> Option<bool> {
+    match opcode {
+        Opcode::Iadd | Opcode::Isub | Opcode::Imul | Opcode::Udiv | Opcode::Urem |
+        Opcode::Band | Opcode::Bor | Opcode::Bxor | Opcode::Ishl | Opcode::Ushr => Some(false),
+        Opcode::Sdiv | Opcode::Srem | Opcode::Sshr => Some(true),
+        _ => None,
+    }
+}
+
+/// Does `cond` compare its operands as signed values?
+fn is_signed_cond(cond: IntCC) -> bool {
+    match cond {
+        IntCC::SignedLessThan | IntCC::SignedGreaterThanOrEqual | IntCC::SignedGreaterThan |
+        IntCC::SignedLessThanOrEqual => true,
+        _ => false,
+    }
+}
+
+/// Widen `arg` from its narrow type up to `i32`, using sign or zero extension as appropriate.
+fn widen(pos: &mut Cursor, arg: ir::Value, signed: bool) -> ir::Value {
+    if signed {
+        pos.ins().sextend(types::I32, arg)
+    } else {
+        pos.ins().uextend(types::I32, arg)
+    }
+}
+
+/// Widen a shift-amount operand for `narrow_ty`. Unlike the shifted value, the shift amount must
+/// not be sign/zero-extended and used as-is: `i32` shifts use the low 5 bits of their amount,
+/// while the narrow op only looks at the low log2(`narrow_ty.bits()`) bits, so a count in
+/// `[narrow_ty.bits(), 31]` would silently change meaning once widened. Zero-extend it and then
+/// mask it down to the range the narrow shift actually wraps on.
+fn widen_shift_amount(pos: &mut Cursor, arg: ir::Value, narrow_ty: ir::Type) -> ir::Value {
+    let wide = pos.ins().uextend(types::I32, arg);
+    pos.ins().band_imm(wide, shift_amount_mask(narrow_ty.bits()))
+}
+
+/// The mask that keeps a widened shift amount within the range `narrow_ty_bits`-wide shift wraps
+/// on, e.g. `0b111` for an `i8` shift so a widened count of 8 through 31 doesn't silently change
+/// meaning. Factored out of `widen_shift_amount` so the arithmetic can be checked on its own.
+fn shift_amount_mask(narrow_ty_bits: u16) -> i64 {
+    i64::from(narrow_ty_bits) - 1
+}
+
+/// Emit the `i32` counterpart of `opcode` applied to `args`, returning its single result value.
+fn emit_wide(pos: &mut Cursor, opcode: Opcode, args: &[ir::Value]) -> ir::Value {
+    let inst = match opcode {
+        Opcode::Iadd => pos.ins().iadd(args[0], args[1]),
+        Opcode::Isub => pos.ins().isub(args[0], args[1]),
+        Opcode::Imul => pos.ins().imul(args[0], args[1]),
+        Opcode::Udiv => pos.ins().udiv(args[0], args[1]),
+        Opcode::Urem => pos.ins().urem(args[0], args[1]),
+        Opcode::Sdiv => pos.ins().sdiv(args[0], args[1]),
+        Opcode::Srem => pos.ins().srem(args[0], args[1]),
+        Opcode::Band => pos.ins().band(args[0], args[1]),
+        Opcode::Bor => pos.ins().bor(args[0], args[1]),
+        Opcode::Bxor => pos.ins().bxor(args[0], args[1]),
+        Opcode::Ishl => pos.ins().ishl(args[0], args[1]),
+        Opcode::Ushr => pos.ins().ushr(args[0], args[1]),
+        Opcode::Sshr => pos.ins().sshr(args[0], args[1]),
+        _ => panic!("{} is not a promotable opcode", opcode),
+    };
+    inst
+}
+
+/// Try to legalize `inst` by promoting its controlling `i8`/`i16` type variable to `i32`.
+///
+/// Returns `true` if the instruction was replaced.
+pub fn expand_promote(
+    dfg: &mut DataFlowGraph,
+    _cfg: &mut ControlFlowGraph,
+    pos: &mut Cursor,
+) -> bool {
+    let inst = pos.current_inst().expect("got disconnected instruction");
+    let opcode = dfg[inst].opcode();
+    let ctrl_ty = dfg.ctrl_typevar(inst);
+
+    if ctrl_ty != types::I8 && ctrl_ty != types::I16 {
+        return false;
+    }
+
+    if opcode == Opcode::Icmp {
+        let cond = dfg[inst].cond_code().expect("icmp without a condition code");
+        let signed = is_signed_cond(cond);
+        let old_result = dfg.first_result(inst);
+        let args = dfg.inst_args(inst).to_vec();
+
+        pos.remove_inst();
+        let wide_args: Vec<_> = args.iter().map(|&arg| widen(pos, arg, signed)).collect();
+        let result = pos.ins().icmp(cond, wide_args[0], wide_args[1]);
+        dfg.change_to_alias(old_result, result);
+
+        return true;
+    }
+
+    let signed = match signedness(opcode) {
+        Some(signed) => signed,
+        None => {
+            panic!(
+                "{} has no narrow-to-{} promotion legalization, but the ISA requested one",
+                opcode,
+                ctrl_ty
+            )
+        }
+    };
+
+    let old_result = dfg.first_result(inst);
+    let args = dfg.inst_args(inst).to_vec();
+    let is_shift = opcode == Opcode::Ishl || opcode == Opcode::Ushr || opcode == Opcode::Sshr;
+
+    pos.remove_inst();
+    let wide_args: Vec<_> = args.iter()
+        .enumerate()
+        .map(|(i, &arg)| if is_shift && i == 1 {
+            widen_shift_amount(pos, arg, ctrl_ty)
+        } else {
+            widen(pos, arg, signed)
+        })
+        .collect();
+    let wide_result = emit_wide(pos, opcode, &wide_args);
+    let narrow_result = pos.ins().ireduce(ctrl_ty, wide_result);
+    dfg.change_to_alias(old_result, narrow_result);
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_signed_cond, shift_amount_mask, signedness};
+    use ir::condcodes::IntCC;
+    use ir::Opcode;
+
+    #[test]
+    fn signedness_picks_sign_or_zero_extension() {
+        assert_eq!(signedness(Opcode::Iadd), Some(false));
+        assert_eq!(signedness(Opcode::Udiv), Some(false));
+        assert_eq!(signedness(Opcode::Ishl), Some(false));
+        assert_eq!(signedness(Opcode::Sdiv), Some(true));
+        assert_eq!(signedness(Opcode::Sshr), Some(true));
+    }
+
+    #[test]
+    fn signedness_is_none_for_unhandled_opcodes() {
+        // `Icmp` is handled separately by `expand_promote`, not through this table.
+        assert_eq!(signedness(Opcode::Icmp), None);
+        assert_eq!(signedness(Opcode::Call), None);
+    }
+
+    #[test]
+    fn is_signed_cond_matches_only_signed_comparisons() {
+        assert!(is_signed_cond(IntCC::SignedLessThan));
+        assert!(is_signed_cond(IntCC::SignedGreaterThan));
+        assert!(!is_signed_cond(IntCC::UnsignedLessThan));
+        assert!(!is_signed_cond(IntCC::Equal));
+    }
+
+    #[test]
+    fn shift_amount_mask_keeps_only_the_narrow_width_bits() {
+        // An i8 shift only wraps on the low 3 bits of its amount: a widened count of 8..31 must
+        // land back in 0..7, not be used as-is.
+        assert_eq!(shift_amount_mask(8), 0b111);
+        assert_eq!(15i64 & shift_amount_mask(8), 7);
+        assert_eq!(8i64 & shift_amount_mask(8), 0);
+
+        assert_eq!(shift_amount_mask(16), 0b1111);
+        assert_eq!(31i64 & shift_amount_mask(16), 15);
+    }
+}
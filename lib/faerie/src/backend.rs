@@ -2,6 +2,7 @@
 
 use container;
 use cretonne::binemit::{Addend, CodeOffset, Reloc, RelocSink, TrapSink};
+use cretonne::entity::PrimaryMap;
 use cretonne::isa::TargetIsa;
 use cretonne::result::CtonError;
 use cretonne::{self, binemit, ir};
@@ -13,13 +14,27 @@ use target;
 
 pub struct FaerieCompiledFunction {}
 
-pub struct FaerieCompiledData {}
+/// A compiled data object.
+///
+/// `define_data` only has the module-local `FuncRef`/`GlobalVar` indices to work with, so it
+/// keeps the declaration tables around until `finalize_data` runs with a `ModuleNamespace` that
+/// can translate them into the linker symbol names the embedded relocations need.
+pub struct FaerieCompiledData {
+    name: String,
+    function_decls: PrimaryMap<ir::FuncRef, ir::ExternalName>,
+    data_decls: PrimaryMap<ir::GlobalVar, ir::ExternalName>,
+    function_relocs: Vec<(CodeOffset, ir::FuncRef)>,
+    data_relocs: Vec<(CodeOffset, ir::GlobalVar, Addend)>,
+}
 
 /// A `FaerieBackend` implements `Backend` and emits ".o" files using the `faerie` library.
 pub struct FaerieBackend<'isa> {
     isa: &'isa TargetIsa,
     artifact: faerie::Artifact,
     format: container::Format,
+    // Trap entries collected per function by `define_function`, serialized into the object's
+    // trap-metadata section the first time `emit`/`write` is called.
+    traps: Vec<(String, Vec<(CodeOffset, ir::SourceLoc, ir::TrapCode)>)>,
 }
 
 impl<'isa> FaerieBackend<'isa> {
@@ -32,25 +47,163 @@ impl<'isa> FaerieBackend<'isa> {
         debug_assert!(isa.flags().is_pic(), "faerie requires PIC");
         Ok(Self {
             isa,
-            artifact: faerie::Artifact::new(target::translate(isa)?, name),
+            artifact: faerie::Artifact::new(target::translate(isa, format)?, name),
             format,
+            traps: Vec::new(),
         })
     }
 
     /// Call `emit` on the faerie `Artifact`, producing bytes in memory.
-    pub fn emit(&self) -> Result<Vec<u8>, Error> {
+    pub fn emit(&mut self) -> Result<Vec<u8>, Error> {
+        self.emit_trap_section();
         match self.format {
             container::Format::ELF => self.artifact.emit::<faerie::Elf>(),
             container::Format::MachO => self.artifact.emit::<faerie::Mach>(),
+            container::Format::COFF => self.artifact.emit::<faerie::Coff>(),
         }
     }
 
     /// Call `write` on the faerie `Artifact`, writing to a file.
-    pub fn write(&self, sink: File) -> Result<(), Error> {
+    pub fn write(&mut self, sink: File) -> Result<(), Error> {
+        self.emit_trap_section();
         match self.format {
             container::Format::ELF => self.artifact.write::<faerie::Elf>(sink),
             container::Format::MachO => self.artifact.write::<faerie::Mach>(sink),
+            container::Format::COFF => self.artifact.write::<faerie::Coff>(sink),
+        }
+    }
+
+    /// Serialize the trap entries collected so far into a dedicated `CRETONNE_TRAPS` section.
+    ///
+    /// The section holds one sorted-by-offset table of `(code offset, source location, trap
+    /// code)` triples per function, plus an index of `(function symbol, table symbol, entry
+    /// count)` records so a runtime can map a faulting address back to its function, then binary
+    /// search that function's table for the originating `TrapCode`/`SourceLoc`. The entry count
+    /// is plain data, not a relocation: only the first two 8-byte slots of a record are pointers
+    /// that need resolving against a symbol, so they're left zeroed here and patched by
+    /// `link_with` below, while the count is written directly into the third slot.
+    fn emit_trap_section(&mut self) {
+        if self.traps.is_empty() {
+            return;
+        }
+
+        let mut index_entries = Vec::with_capacity(self.traps.len());
+        for (name, mut entries) in self.traps.drain(..) {
+            entries.sort_by_key(|&(offset, _, _)| offset);
+
+            let mut table = Vec::with_capacity(entries.len() * TRAP_ENTRY_SIZE);
+            for (offset, srcloc, code) in &entries {
+                table.extend_from_slice(&offset.to_le_bytes());
+                table.extend_from_slice(&srcloc.bits().to_le_bytes());
+                table.extend_from_slice(&trap_code_tag(*code).to_le_bytes());
+            }
+
+            let table_symbol = format!("{}$traps", name);
+            self.artifact.define(&table_symbol, table).expect(
+                "inconsistent declaration",
+            );
+            index_entries.push((name, table_symbol, entries.len() as u64));
+        }
+
+        let mut index = Vec::with_capacity(index_entries.len() * TRAP_INDEX_ENTRY_SIZE);
+        for &(_, _, count) in &index_entries {
+            index.extend_from_slice(&[0u8; 8]); // function symbol pointer, relocated below
+            index.extend_from_slice(&[0u8; 8]); // trap table pointer, relocated below
+            index.extend_from_slice(&count.to_le_bytes());
         }
+        self.artifact.define("CRETONNE_TRAPS", index).expect(
+            "inconsistent declaration",
+        );
+
+        let abs_reloc = container::raw_relocation(Reloc::Abs8, self.format, self.isa.name());
+        for (i, (func_symbol, table_symbol, _)) in index_entries.into_iter().enumerate() {
+            let at = i * TRAP_INDEX_ENTRY_SIZE;
+            self.artifact
+                .link_with(
+                    faerie::Link { from: "CRETONNE_TRAPS", to: &func_symbol, at },
+                    faerie::RelocOverride { reloc: abs_reloc, addend: 0 },
+                )
+                .expect("faerie relocation error");
+            self.artifact
+                .link_with(
+                    faerie::Link {
+                        from: "CRETONNE_TRAPS",
+                        to: &table_symbol,
+                        at: at + 8,
+                    },
+                    faerie::RelocOverride { reloc: abs_reloc, addend: 0 },
+                )
+                .expect("faerie relocation error");
+        }
+    }
+}
+
+/// Size in bytes of one `(code offset, source location, trap code)` entry in a function's trap
+/// table: a `u32` code offset, a `u32` source location, and a `u32` trap code tag.
+const TRAP_ENTRY_SIZE: usize = 12;
+
+/// Size in bytes of one `CRETONNE_TRAPS` index record: an 8-byte pointer to the function symbol,
+/// an 8-byte pointer to its trap table, and an 8-byte entry count (plain data, not a relocation).
+const TRAP_INDEX_ENTRY_SIZE: usize = 24;
+
+/// Narrow a data relocation's `Addend` down to the `i32` `faerie::RelocOverride` expects,
+/// asserting that doing so doesn't lose any bits: `write_data_dataaddr` offsets are meant to be
+/// small displacements within an object, so a value that doesn't fit is a sign something upstream
+/// computed the wrong addend rather than something to silently truncate.
+fn narrow_addend(addend: Addend) -> i32 {
+    let narrowed = addend as i32;
+    debug_assert!(narrowed as i64 == addend);
+    narrowed
+}
+
+#[cfg(test)]
+mod narrow_addend_tests {
+    use super::narrow_addend;
+
+    #[test]
+    fn addends_within_i32_range_round_trip() {
+        assert_eq!(narrow_addend(0), 0);
+        assert_eq!(narrow_addend(-8), -8);
+        assert_eq!(narrow_addend(i64::from(i32::max_value())), i32::max_value());
+        assert_eq!(narrow_addend(i64::from(i32::min_value())), i32::min_value());
+    }
+
+    #[test]
+    #[should_panic]
+    fn addends_outside_i32_range_panic_in_debug_builds() {
+        narrow_addend(i64::from(i32::max_value()) + 1);
+    }
+}
+
+/// Map a `TrapCode` to the small integer tag stored in a trap table entry.
+fn trap_code_tag(code: ir::TrapCode) -> u32 {
+    match code {
+        ir::TrapCode::StackOverflow => 0,
+        ir::TrapCode::HeapOutOfBounds => 1,
+        ir::TrapCode::IntegerOverflow => 2,
+        ir::TrapCode::IntegerDivisionByZero => 3,
+        ir::TrapCode::BadSignature => 4,
+        ir::TrapCode::UnreachableCodeReached => 5,
+        ir::TrapCode::User(code) => 0x8000_0000 | u32::from(code),
+    }
+}
+
+#[cfg(test)]
+mod trap_code_tag_tests {
+    use super::trap_code_tag;
+    use cretonne::ir::TrapCode;
+
+    #[test]
+    fn builtin_codes_get_distinct_small_tags() {
+        assert_eq!(trap_code_tag(TrapCode::StackOverflow), 0);
+        assert_eq!(trap_code_tag(TrapCode::HeapOutOfBounds), 1);
+        assert_eq!(trap_code_tag(TrapCode::UnreachableCodeReached), 5);
+    }
+
+    #[test]
+    fn user_codes_are_tagged_with_the_high_bit_set() {
+        assert_eq!(trap_code_tag(TrapCode::User(0)), 0x8000_0000);
+        assert_eq!(trap_code_tag(TrapCode::User(5)), 0x8000_0005);
     }
 }
 
@@ -89,17 +242,41 @@ impl<'isa> Backend for FaerieBackend<'isa> {
         let mut code: Vec<u8> = Vec::with_capacity(code_size as usize);
         code.resize(code_size as usize, 0);
 
+        // Declare a read-only data symbol for each jump table used by the function, so that a
+        // `br_table`-style dispatch can take the address of its table with an ordinary
+        // relocation. The bytes are left zeroed here; `FaerieRelocSink` fills in each entry with
+        // a relocation against the function's own symbol as the ISA emits the table (see the
+        // `pending_jt` tracking there).
+        let mut jump_tables = PrimaryMap::new();
+        for (jt, jt_data) in &ctx.func.jump_tables {
+            let jt_symbol = format!("{}$jt{}", name, jump_tables.len());
+            self.artifact
+                .define(&jt_symbol, vec![0u8; jt_data.len() * 4])
+                .expect("inconsistent declaration");
+            let inserted = jump_tables.push((jt_symbol, jt_data.len()));
+            debug_assert_eq!(inserted, jt);
+        }
+
         // Non-lexical lifetimes would obviate the braces here.
-        {
+        let collected_traps = {
             let mut reloc_sink = FaerieRelocSink {
                 format: self.format,
+                isa_name: self.isa.name(),
                 artifact: &mut self.artifact,
                 name,
                 namespace,
+                jump_tables: &jump_tables,
+                pending_jt: None,
             };
-            let mut trap_sink = FaerieTrapSink {};
+            let mut trap_sink = FaerieTrapSink::new();
 
             ctx.emit_to_memory(code.as_mut_ptr(), &mut reloc_sink, &mut trap_sink, self.isa);
+
+            trap_sink.traps
+        };
+
+        if !collected_traps.is_empty() {
+            self.traps.push((name.to_owned(), collected_traps));
         }
 
         self.artifact.define(name, code).expect(
@@ -108,27 +285,39 @@ impl<'isa> Backend for FaerieBackend<'isa> {
         Ok(FaerieCompiledFunction {})
     }
 
-    fn define_data(&mut self, _name: &str, _data: &DataContext) -> FaerieCompiledData {
-        unimplemented!()
+    fn define_data(&mut self, name: &str, data: &DataContext) -> FaerieCompiledData {
+        let desc = data.description();
+
+        self.artifact.define(name, desc.data.to_vec()).expect(
+            "inconsistent declaration",
+        );
+
+        FaerieCompiledData {
+            name: name.to_owned(),
+            function_decls: desc.function_decls.clone(),
+            data_decls: desc.data_decls.clone(),
+            function_relocs: Vec::new(),
+            data_relocs: Vec::new(),
+        }
     }
 
     fn write_data_funcaddr(
         &mut self,
-        _data: &mut FaerieCompiledData,
-        _offset: usize,
-        _what: ir::FuncRef,
+        data: &mut FaerieCompiledData,
+        offset: usize,
+        what: ir::FuncRef,
     ) {
-        unimplemented!()
+        data.function_relocs.push((offset as CodeOffset, what));
     }
 
     fn write_data_dataaddr(
         &mut self,
-        _data: &mut FaerieCompiledData,
-        _offset: usize,
-        _what: ir::GlobalVar,
-        _usize: binemit::Addend,
+        data: &mut FaerieCompiledData,
+        offset: usize,
+        what: ir::GlobalVar,
+        addend: binemit::Addend,
     ) {
-        unimplemented!()
+        data.data_relocs.push((offset as CodeOffset, what, addend));
     }
 
     fn finalize_function(
@@ -139,11 +328,48 @@ impl<'isa> Backend for FaerieBackend<'isa> {
         // Nothing to do.
     }
 
-    fn finalize_data(&mut self, _data: &FaerieCompiledData, _namespace: &ModuleNamespace<Self>) {
-        // Nothing to do.
+    fn finalize_data(&mut self, data: &FaerieCompiledData, namespace: &ModuleNamespace<Self>) {
+        for &(offset, func_ref) in &data.function_relocs {
+            let ref_name = &namespace.get_function_decl(&data.function_decls[func_ref]).name;
+            self.artifact
+                .link_with(
+                    faerie::Link {
+                        from: &data.name,
+                        to: ref_name,
+                        at: offset as usize,
+                    },
+                    faerie::RelocOverride {
+                        reloc: container::raw_relocation(Reloc::Abs8, self.format, self.isa.name()),
+                        addend: 0,
+                    },
+                )
+                .expect("faerie relocation error");
+        }
+
+        for &(offset, global_var, addend) in &data.data_relocs {
+            let ref_name = &namespace.get_data_decl(&data.data_decls[global_var]).name;
+            let addend_i32 = narrow_addend(addend);
+            self.artifact
+                .link_with(
+                    faerie::Link {
+                        from: &data.name,
+                        to: ref_name,
+                        at: offset as usize,
+                    },
+                    faerie::RelocOverride {
+                        reloc: container::raw_relocation(Reloc::Abs8, self.format, self.isa.name()),
+                        addend: addend_i32,
+                    },
+                )
+                .expect("faerie relocation error");
+        }
     }
 }
 
+// `faerie::Decl` is a container-agnostic description of a symbol's linkage; `faerie` itself is
+// responsible for lowering it to the right storage class for whichever of ELF/Mach-O/COFF is
+// being emitted, so these translations don't need a `Format` to pick between.
+
 fn translate_function_linkage(linkage: Linkage) -> faerie::Decl {
     match linkage {
         Linkage::Import => faerie::Decl::FunctionImport,
@@ -168,14 +394,64 @@ fn translate_data_linkage(linkage: Linkage, writable: bool) -> faerie::Decl {
 
 struct FaerieRelocSink<'a, 'isa: 'a> {
     format: container::Format,
+    isa_name: &'a str,
     artifact: &'a mut faerie::Artifact,
     name: &'a str,
     namespace: &'a ModuleNamespace<'a, FaerieBackend<'isa>>,
+    jump_tables: &'a PrimaryMap<ir::JumpTable, (String, usize)>,
+    // Set by `reloc_jt` to `(jt, next entry index)`: the ISA emits a jump table's entries as a
+    // contiguous run of `reloc_ebb` calls immediately after the `reloc_jt` call for that same
+    // table, so while this is `Some` the next `reloc_ebb` calls are that table's entries, not
+    // ordinary intra-function branches, and must be written into the table's own symbol rather
+    // than into `self.name`.
+    pending_jt: Option<(ir::JumpTable, usize)>,
 }
 
 impl<'a, 'isa> RelocSink for FaerieRelocSink<'a, 'isa> {
-    fn reloc_ebb(&mut self, _offset: CodeOffset, _reloc: Reloc, _ebb_offset: CodeOffset) {
-        unimplemented!();
+    fn reloc_ebb(&mut self, offset: CodeOffset, reloc: Reloc, ebb_offset: CodeOffset) {
+        let raw_reloc = container::raw_relocation(reloc, self.format, self.isa_name);
+
+        if let Some((jt, index)) = self.pending_jt {
+            // This is one entry of a jump table: write the destination block's offset into the
+            // table's own symbol rather than treating it as a branch within `self.name`.
+            let (ref jt_symbol, len) = self.jump_tables[jt];
+            self.artifact
+                .link_with(
+                    faerie::Link {
+                        from: jt_symbol,
+                        to: self.name,
+                        at: index * 4,
+                    },
+                    faerie::RelocOverride {
+                        reloc: raw_reloc,
+                        addend: ebb_offset as i32,
+                    },
+                )
+                .expect("faerie relocation error");
+
+            self.pending_jt = if index + 1 < len {
+                Some((jt, index + 1))
+            } else {
+                None
+            };
+            return;
+        }
+
+        // The branch target is another block of this same function, so the relocation resolves
+        // against our own symbol, offset by how far into the function the target block lies.
+        self.artifact
+            .link_with(
+                faerie::Link {
+                    from: self.name,
+                    to: self.name,
+                    at: offset as usize,
+                },
+                faerie::RelocOverride {
+                    reloc: raw_reloc,
+                    addend: ebb_offset as i32,
+                },
+            )
+            .expect("faerie relocation error");
     }
 
     fn reloc_external(
@@ -187,7 +463,7 @@ impl<'a, 'isa> RelocSink for FaerieRelocSink<'a, 'isa> {
     ) {
         let ref_name = &self.namespace.get_function_decl(name).name;
         let addend_i32 = addend as i32;
-        let raw_reloc = container::raw_relocation(reloc, self.format);
+        let raw_reloc = container::raw_relocation(reloc, self.format, self.isa_name);
         debug_assert!(addend_i32 as i64 == addend);
         self.artifact
             .link_with(
@@ -204,14 +480,43 @@ impl<'a, 'isa> RelocSink for FaerieRelocSink<'a, 'isa> {
             .expect("faerie relocation error");
     }
 
-    fn reloc_jt(&mut self, _offset: CodeOffset, _reloc: Reloc, _jt: ir::JumpTable) {
-        unimplemented!();
+    fn reloc_jt(&mut self, offset: CodeOffset, reloc: Reloc, jt: ir::JumpTable) {
+        let (ref jt_symbol, len) = self.jump_tables[jt];
+        let raw_reloc = container::raw_relocation(reloc, self.format, self.isa_name);
+        self.artifact
+            .link_with(
+                faerie::Link {
+                    from: self.name,
+                    to: jt_symbol,
+                    at: offset as usize,
+                },
+                faerie::RelocOverride {
+                    reloc: raw_reloc,
+                    addend: 0,
+                },
+            )
+            .expect("faerie relocation error");
+
+        // The table's entries are emitted as the next `len` `reloc_ebb` calls; route them into
+        // the table's own symbol instead of treating them as branches within `self.name`.
+        self.pending_jt = if len > 0 { Some((jt, 0)) } else { None };
     }
 }
 
-struct FaerieTrapSink {}
+/// Accumulates the traps emitted for a single function, so `FaerieBackend` can serialize them
+/// into the object's trap-metadata section once the function is done emitting.
+struct FaerieTrapSink {
+    traps: Vec<(CodeOffset, ir::SourceLoc, ir::TrapCode)>,
+}
+
+impl FaerieTrapSink {
+    fn new() -> Self {
+        Self { traps: Vec::new() }
+    }
+}
 
 impl TrapSink for FaerieTrapSink {
-    // Ignore traps for now. For now, frontends should just avoid generating code that traps.
-    fn trap(&mut self, _offset: CodeOffset, _srcloc: ir::SourceLoc, _code: ir::TrapCode) {}
+    fn trap(&mut self, offset: CodeOffset, srcloc: ir::SourceLoc, code: ir::TrapCode) {
+        self.traps.push((offset, srcloc, code));
+    }
 }
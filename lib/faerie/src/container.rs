@@ -0,0 +1,157 @@
+//! Container format selection and relocation-type translation for `FaerieBackend`.
+//!
+//! Cretonne's `Reloc` enum is a lowest-common-denominator description of a relocation ("an
+//! absolute 8-byte address", "a PC-relative 4-byte branch displacement", ...). Each container
+//! format has its own, differently-numbered relocation-type enumeration, so this module is the
+//! one place that translates between the two.
+
+use cretonne::binemit::Reloc;
+
+/// Which object file container `FaerieBackend` should emit.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    ELF,
+    MachO,
+    COFF,
+}
+
+/// Translate `reloc` into the raw relocation type number `format` uses for it on `isa_name`
+/// (`isa.name()`). Only `COFF` currently varies by machine: ELF and Mach-O output from this
+/// backend is x86_64-only so far, so their tables don't need one.
+pub fn raw_relocation(reloc: Reloc, format: Format, isa_name: &str) -> u32 {
+    match format {
+        Format::ELF => {
+            match reloc {
+                Reloc::Abs4 => elf::R_X86_64_32,
+                Reloc::Abs8 => elf::R_X86_64_64,
+                Reloc::X86PCRel4 | Reloc::X86PCRelRodata4 => elf::R_X86_64_PC32,
+                Reloc::X86GOTPCRel4 => elf::R_X86_64_GOTPCREL,
+                Reloc::X86CallPCRel4 | Reloc::X86CallPLTRel4 => elf::R_X86_64_PLT32,
+                _ => panic!("unsupported relocation {:?} for ELF", reloc),
+            }
+        }
+        Format::MachO => {
+            match reloc {
+                Reloc::Abs4 | Reloc::Abs8 => macho::X86_64_RELOC_UNSIGNED,
+                Reloc::X86PCRel4 | Reloc::X86PCRelRodata4 => macho::X86_64_RELOC_SIGNED,
+                Reloc::X86GOTPCRel4 => macho::X86_64_RELOC_GOT,
+                Reloc::X86CallPCRel4 | Reloc::X86CallPLTRel4 => macho::X86_64_RELOC_BRANCH,
+                _ => panic!("unsupported relocation {:?} for Mach-O", reloc),
+            }
+        }
+        Format::COFF => {
+            match isa_name {
+                "arm64" => {
+                    match reloc {
+                        Reloc::Abs4 => coff::arm64::IMAGE_REL_ARM64_ADDR32,
+                        Reloc::Abs8 => coff::arm64::IMAGE_REL_ARM64_ADDR64,
+                        Reloc::X86PCRel4 | Reloc::X86PCRelRodata4 | Reloc::X86GOTPCRel4 |
+                        Reloc::X86CallPCRel4 | Reloc::X86CallPLTRel4 => {
+                            coff::arm64::IMAGE_REL_ARM64_REL32
+                        }
+                        _ => panic!("unsupported relocation {:?} for ARM64 COFF", reloc),
+                    }
+                }
+                _ => {
+                    match reloc {
+                        Reloc::Abs4 => coff::amd64::IMAGE_REL_AMD64_ADDR32,
+                        Reloc::Abs8 => coff::amd64::IMAGE_REL_AMD64_ADDR64,
+                        // COFF has no GOT/PLT-relative relocation kinds; PIC on this target is
+                        // expected to be achieved some other way (e.g. `/DYNAMICBASE` + a
+                        // relocation table), so fall back to an ordinary `REL32` the same as a
+                        // direct PC-relative reference.
+                        Reloc::X86PCRel4 | Reloc::X86PCRelRodata4 | Reloc::X86GOTPCRel4 |
+                        Reloc::X86CallPCRel4 | Reloc::X86CallPLTRel4 => {
+                            coff::amd64::IMAGE_REL_AMD64_REL32
+                        }
+                        _ => panic!("unsupported relocation {:?} for AMD64 COFF", reloc),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// `R_X86_64_*` relocation type numbers, as defined by the ELF x86-64 psABI.
+mod elf {
+    pub const R_X86_64_64: u32 = 1;
+    pub const R_X86_64_PC32: u32 = 2;
+    pub const R_X86_64_PLT32: u32 = 4;
+    pub const R_X86_64_GOTPCREL: u32 = 9;
+    pub const R_X86_64_32: u32 = 10;
+}
+
+/// `X86_64_RELOC_*` relocation type numbers, as defined by Mach-O's `reloc_type_x86_64`.
+mod macho {
+    pub const X86_64_RELOC_UNSIGNED: u32 = 0;
+    pub const X86_64_RELOC_SIGNED: u32 = 1;
+    pub const X86_64_RELOC_BRANCH: u32 = 2;
+    pub const X86_64_RELOC_GOT: u32 = 4;
+}
+
+/// PE/COFF relocation type numbers, as defined by the PE/COFF specification. These are
+/// machine-specific: the same numeric value means different things (or nothing) on a different
+/// machine type, so AMD64 and ARM64 each get their own table.
+mod coff {
+    /// `IMAGE_REL_AMD64_*`.
+    pub mod amd64 {
+        pub const IMAGE_REL_AMD64_ADDR64: u32 = 0x0001;
+        pub const IMAGE_REL_AMD64_ADDR32: u32 = 0x0002;
+        pub const IMAGE_REL_AMD64_REL32: u32 = 0x0004;
+    }
+
+    /// `IMAGE_REL_ARM64_*`.
+    pub mod arm64 {
+        pub const IMAGE_REL_ARM64_ADDR32: u32 = 0x0001;
+        pub const IMAGE_REL_ARM64_ADDR64: u32 = 0x000e;
+        pub const IMAGE_REL_ARM64_REL32: u32 = 0x0011;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{coff, elf, macho, raw_relocation, Format};
+    use cretonne::binemit::Reloc;
+
+    #[test]
+    fn abs8_maps_to_each_format_absolute_relocation() {
+        assert_eq!(raw_relocation(Reloc::Abs8, Format::ELF, "intel"), elf::R_X86_64_64);
+        assert_eq!(
+            raw_relocation(Reloc::Abs8, Format::MachO, "intel"),
+            macho::X86_64_RELOC_UNSIGNED
+        );
+        assert_eq!(
+            raw_relocation(Reloc::Abs8, Format::COFF, "intel"),
+            coff::amd64::IMAGE_REL_AMD64_ADDR64
+        );
+    }
+
+    #[test]
+    fn coff_collapses_pc_relative_kinds_to_rel32() {
+        assert_eq!(
+            raw_relocation(Reloc::X86PCRel4, Format::COFF, "intel"),
+            coff::amd64::IMAGE_REL_AMD64_REL32
+        );
+        assert_eq!(
+            raw_relocation(Reloc::X86GOTPCRel4, Format::COFF, "intel"),
+            coff::amd64::IMAGE_REL_AMD64_REL32
+        );
+        assert_eq!(
+            raw_relocation(Reloc::X86CallPCRel4, Format::COFF, "intel"),
+            coff::amd64::IMAGE_REL_AMD64_REL32
+        );
+    }
+
+    #[test]
+    fn arm64_coff_uses_the_arm64_relocation_table() {
+        assert_eq!(
+            raw_relocation(Reloc::Abs8, Format::COFF, "arm64"),
+            coff::arm64::IMAGE_REL_ARM64_ADDR64
+        );
+        assert_ne!(coff::arm64::IMAGE_REL_ARM64_ADDR64, coff::amd64::IMAGE_REL_AMD64_ADDR64);
+        assert_eq!(
+            raw_relocation(Reloc::X86PCRel4, Format::COFF, "arm64"),
+            coff::arm64::IMAGE_REL_ARM64_REL32
+        );
+    }
+}
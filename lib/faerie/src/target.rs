@@ -0,0 +1,29 @@
+//! Translate a Cretonne `TargetIsa` into the target triple `faerie::Artifact::new` expects.
+
+use container::Format;
+use cretonne::isa::TargetIsa;
+use failure::Error;
+use target_lexicon::Triple;
+
+/// Translate `isa` into the `Triple` `faerie` needs to build its `Artifact`, rejecting up front
+/// any combination `format` can't actually represent rather than failing later inside `faerie`.
+pub fn translate(isa: &TargetIsa, format: Format) -> Result<Triple, Error> {
+    let triple = isa.triple().clone();
+
+    if format == Format::COFF {
+        // `container::raw_relocation` only has AMD64 (`"intel"`) and ARM64 (`"arm64"`) relocation
+        // tables for `Format::COFF`; reject every other machine up front instead of panicking
+        // inside `raw_relocation` on the first relocation `emit_to_memory` produces.
+        match isa.name() {
+            "intel" | "arm64" => {}
+            name => {
+                return Err(format_err!(
+                    "COFF output is not supported for the `{}` target",
+                    name
+                ))
+            }
+        }
+    }
+
+    Ok(triple)
+}
@@ -8,20 +8,34 @@ use cranelift_codegen::settings::FlagsOrIsa;
 use cranelift_codegen::timing;
 use cranelift_codegen::Context;
 use cranelift_reader::parse_test;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::path::Path;
 use std::path::PathBuf;
+use std::process::Command;
 
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 
-pub fn run(filename: &str, flag_set: &[String], flag_isa: &str) -> Result<(), String> {
+pub fn run(
+    filename: &str,
+    flag_set: &[String],
+    flag_isa: &str,
+    test_command: Option<&str>,
+) -> Result<(), String> {
     let parsed = parse_sets_and_triple(flag_set, flag_isa)?;
 
     let path = Path::new(&filename);
     let name = String::from(path.as_os_str().to_string_lossy());
-    handle_module(&path.to_path_buf(), &name, parsed.as_fisa())
+    handle_module(&path.to_path_buf(), &name, parsed.as_fisa(), test_command)
 }
 
-fn handle_module(path: &PathBuf, name: &str, fisa: FlagsOrIsa) -> Result<(), String> {
+fn handle_module(
+    path: &PathBuf,
+    name: &str,
+    fisa: FlagsOrIsa,
+    test_command: Option<&str>,
+) -> Result<(), String> {
     let buffer = read_to_string(&path).map_err(|e| format!("{}: {}", name, e))?;
     let test_file = parse_test(&buffer, None, None).map_err(|e| format!("{}: {}", name, e))?;
 
@@ -38,7 +52,7 @@ fn handle_module(path: &PathBuf, name: &str, fisa: FlagsOrIsa) -> Result<(), Str
     std::env::set_var("RUST_BACKTRACE", "0"); // Disable backtraces to reduce verbosity
 
     for (func, _) in test_file.functions {
-        reduce(isa, func);
+        reduce(isa, func, test_command);
     }
 
     //print!("{}", timing::take_current());
@@ -144,7 +158,12 @@ fn next_inst_ret_prev(func: &Function, ebb: &mut Ebb, inst: &mut Inst) -> Option
     }
 }
 
-fn reduce(isa: &TargetIsa, mut func: Function) {
+fn reduce(isa: &TargetIsa, mut func: Function, test_command: Option<&str>) {
+    // The external command is by far the dominant cost when one is given, and the same
+    // candidate function is frequently produced more than once across phases/passes. Cache
+    // verdicts keyed by a hash of the function's textual form so we don't re-run it.
+    let mut cache: HashMap<u64, Res> = HashMap::new();
+
     'outer_loop: for pass_idx in 0..100 {
         let mut was_reduced = false;
         let first_ebb = func.layout.entry_block().unwrap();
@@ -171,7 +190,13 @@ fn reduce(isa: &TargetIsa, mut func: Function) {
 
             progress.set_message(&msg);
 
-            match check_for_crash(isa, &func2) {
+            let key = hash_function(&func2);
+            let result = cache
+                .entry(key)
+                .or_insert_with(|| check_for_crash(isa, &func2, test_command))
+                .clone();
+
+            match result {
                 Res::Succeed => {
                     // Shrinking didn't hit the problem anymore, discard changes.
                     //progress.println("succeeded");
@@ -182,8 +207,8 @@ fn reduce(isa: &TargetIsa, mut func: Function) {
                     //progress.println(format!("verifier error {}", err));
                     continue;
                 }
-                Res::Panic => {
-                    // Panic remained while shrinking, make changes definitive.
+                Res::Panic | Res::Interesting => {
+                    // The problem remained while shrinking, make changes definitive.
                     was_reduced = true;
                     func = func2;
                     progress.println(format!("{}: shrink", msg));
@@ -201,20 +226,34 @@ fn reduce(isa: &TargetIsa, mut func: Function) {
     println!("{}", func);
 }
 
+/// Hash the textual form of `func`, used to dedupe candidates across shrink passes.
+fn hash_function(func: &Function) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    func.to_string().hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod hash_function_tests {
+    use super::hash_function;
+    use cranelift_codegen::ir::Function;
+
+    #[test]
+    fn identical_functions_hash_the_same() {
+        assert_eq!(hash_function(&Function::new()), hash_function(&Function::new()));
+    }
+}
+
+#[derive(Clone)]
 enum Res {
     Succeed,
     Verifier(String),
     Panic,
+    /// The external `test_command` reported this candidate as still interesting.
+    Interesting,
 }
 
-fn check_for_crash(isa: &TargetIsa, func: &Function) -> Res {
-    let mut context = Context::new();
-    context.func = func.clone();
-
-    let mut relocs = PrintRelocs::new(false);
-    let mut traps = PrintTraps::new(false);
-    let mut mem = vec![];
-
+fn check_for_crash(isa: &TargetIsa, func: &Function, test_command: Option<&str>) -> Res {
     use std::io::Write;
     std::io::stdout().flush().unwrap(); // Flush stdout to sync with panic messages on stderr
 
@@ -233,6 +272,16 @@ fn check_for_crash(isa: &TargetIsa, func: &Function) -> Res {
         }
     }
 
+    if let Some(test_command) = test_command {
+        return check_for_interesting(func, test_command);
+    }
+    let mut context = Context::new();
+    context.func = func.clone();
+
+    let mut relocs = PrintRelocs::new(false);
+    let mut traps = PrintTraps::new(false);
+    let mut mem = vec![];
+
     match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
         if let Err(verifier_err) = context.compile_and_emit(isa, &mut mem, &mut relocs, &mut traps)
         {
@@ -244,4 +293,30 @@ fn check_for_crash(isa: &TargetIsa, func: &Function) -> Res {
         Ok(res) => res,
         Err(_panic) => Res::Panic,
     }
+}
+
+/// Write `func` to a temporary `.clif` file, run `test_command` against it, and treat a zero
+/// exit status as "still interesting" (the same convention as e.g. `creduce`'s interestingness
+/// tests). Any other exit status, or a failure to even launch the command, means the candidate
+/// is uninteresting and should be discarded.
+fn check_for_interesting(func: &Function, test_command: &str) -> Res {
+    let mut path = std::env::temp_dir();
+    path.push(format!("bugpoint-{}.clif", hash_function(func)));
+
+    if let Err(err) = std::fs::write(&path, func.to_string()) {
+        println!("failed to write candidate to {}: {}", path.display(), err);
+        return Res::Succeed;
+    }
+
+    let status = Command::new(test_command).arg(&path).status();
+    let _ = std::fs::remove_file(&path);
+
+    match status {
+        Ok(status) if status.success() => Res::Interesting,
+        Ok(_) => Res::Succeed,
+        Err(err) => {
+            println!("failed to run {}: {}", test_command, err);
+            Res::Succeed
+        }
+    }
 }
\ No newline at end of file